@@ -1,10 +1,12 @@
 use clap::{Args, Parser, Subcommand};
+use futures::stream::{self, StreamExt};
 use icingaplugin_rs::{check::{CheckResult, Metric, PerfData, State}, utils::evaluate};
-use sqlx::{Connection, MySqlConnection, mysql::MySqlRow, Row};
+use regex::Regex;
+use serde::Deserialize;
+use sqlx::{mysql::{MySqlConnectOptions, MySqlPool, MySqlPoolOptions, MySqlRow}, Row};
 use std::convert::TryInto;
 use std::ops::Deref;
 use std::process::exit;
-use futures_lite::stream::StreamExt;
 
 /// A check plugin for retrieving performance data of vSphere hosts collected by Icingaweb2's vSphereDB modul.
 ///
@@ -25,35 +27,182 @@ struct App {
     /// machine to be queried for
     #[clap(short, long)]
     machine: String,
+
+    /// path to a TOML config file supplying database settings and default thresholds;
+    /// values passed on the command line take precedence over values from this file
+    #[clap(long)]
+    config: Option<String>,
+
+    /// output format: "icinga" exits with perfdata for Icinga (default), "prometheus"
+    /// prints the collected measurements in Prometheus text exposition format instead
+    #[clap(long, default_value="icinga")]
+    export: String,
+
+    /// maximum number of hosts to query concurrently when --machine names more than one host
+    #[clap(long, default_value="5")]
+    max_concurrency: usize,
+
+    /// maximum number of pooled database connections
+    #[clap(long, default_value="5")]
+    pool_size: u32,
+
+    /// prepared statement cache policy: "unbounded" keeps every prepared statement around
+    /// (best when the same query runs repeatedly, e.g. batch mode or --export), "disabled"
+    /// never retains one, keeping memory flat for one-shot single-host invocations
+    #[clap(long, default_value="unbounded")]
+    statement_cache: String,
 }
 
-#[derive(Args)]
+#[derive(Args, Clone)]
 struct Settings {
-    /// database host to connect to
-    #[clap(short='H', long, default_value="localhost")]
-    host: String,
+    /// database host to connect to (default: localhost)
+    #[clap(short='H', long)]
+    host: Option<String>,
+
 
+    /// database port to connect to (default: 3306)
+    #[clap(short, long)]
+    port: Option<u16>,
+
+    /// database name (default: vspheredb)
+    #[clap(short, long)]
+    database: Option<String>,
 
-    /// database port to connect to
-    #[clap(short, long, default_value="3306")]
-    port: u16,
+    /// database user (default: vspheredb)
+    #[clap(short, long)]
+    user: Option<String>,
+
+    /// database password (default: vspheredb)
+    #[clap(short='P', long)]
+    password: Option<String>,
+
+}
+
+/// Database connection settings as read from a `[database]` table in the config file.
+#[derive(Deserialize, Default)]
+struct DatabaseConfig {
+    host: Option<String>,
+    port: Option<u16>,
+    database: Option<String>,
+    user: Option<String>,
+    password: Option<String>,
+}
+
+/// Default warning/critical thresholds for a single check, as read from its table
+/// (e.g. `[cpu]`, `[memory]`, `[datastore]`) in the config file.
+#[derive(Deserialize, Default, Clone, Copy)]
+struct ThresholdConfig {
+    warning: Option<u32>,
+    critical: Option<u32>,
+}
+
+/// Layout of the `--config` TOML file: a `[database]` table plus one threshold
+/// table per check type, mirroring `Settings` and the `Checks` threshold fields.
+#[derive(Deserialize, Default)]
+struct ConfigFile {
+    database: Option<DatabaseConfig>,
+    cpu: Option<ThresholdConfig>,
+    memory: Option<ThresholdConfig>,
+    temperature: Option<ThresholdConfig>,
+    nic: Option<ThresholdConfig>,
+    hba: Option<ThresholdConfig>,
+    datastore: Option<ThresholdConfig>,
+}
+
+/// Reads and parses the `--config` file into a `ConfigFile`.
+fn load_config(path: &str) -> Result<ConfigFile, String> {
+    let content = std::fs::read_to_string(path)
+        .map_err(|e| format!("could not read config file {}: {}", path, e))?;
+    toml::from_str(&content)
+        .map_err(|e| format!("could not parse config file {}: {}", path, e))
+}
 
-    /// database name
-    #[clap(short, long, default_value="vspheredb")]
-    database: String,
+#[derive(Args, Clone)]
+struct InterfaceFilter {
+    /// keep only interfaces whose name matches one of these patterns (allow-list)
+    #[clap(long = "interface-include")]
+    interface_include: Vec<String>,
 
-    /// database user
-    #[clap(short, long, default_value="vspheredb")]
-    user: String,
+    /// drop interfaces whose name matches one of these patterns (ignore-list)
+    #[clap(long = "interface-exclude")]
+    interface_exclude: Vec<String>,
 
-    /// database password
-    #[clap(short='P', long, default_value="vspheredb")]
-    password: String,
+    /// treat --interface-include/--interface-exclude patterns as regular expressions
+    #[clap(long = "interface-regex")]
+    interface_regex: bool,
 
+    /// match interface name patterns case-sensitively (default: case-insensitive)
+    #[clap(long = "interface-case-sensitive")]
+    interface_case_sensitive: bool,
+
+    /// require patterns to match a whole word of the interface name rather than any substring
+    #[clap(long = "interface-whole-word")]
+    interface_whole_word: bool,
+}
+
+impl InterfaceFilter {
+    /// Keeps only the names that survive the configured include/exclude filtering.
+    fn apply<'a>(&self, names: &[&'a str]) -> Result<Vec<&'a str>, regex::Error> {
+        let exclude = self.compile(&self.interface_exclude)?;
+        let include = self.compile(&self.interface_include)?;
+
+        Ok(names.iter()
+            .copied()
+            .filter(|name| !exclude.iter().any(|p| p.matches(name)))
+            .filter(|name| include.is_empty() || include.iter().any(|p| p.matches(name)))
+            .collect())
+    }
+
+    fn compile(&self, patterns: &[String]) -> Result<Vec<InterfacePattern>, regex::Error> {
+        patterns.iter()
+            .map(|p| InterfacePattern::compile(p, self.interface_regex, self.interface_case_sensitive, self.interface_whole_word))
+            .collect()
+    }
+}
+
+enum InterfacePattern {
+    Regex(Regex),
+    Literal{ pattern: String, whole_word: bool, case_sensitive: bool },
+}
+
+impl InterfacePattern {
+    fn compile(pattern: &str, regex: bool, case_sensitive: bool, whole_word: bool) -> Result<Self, regex::Error> {
+        if regex {
+            let pattern = if whole_word {
+                format!("\\b(?:{})\\b", pattern)
+            } else {
+                pattern.to_string()
+            };
+            if !case_sensitive {
+                return Ok(InterfacePattern::Regex(Regex::new(&format!("(?i){}", pattern))?));
+            }
+            Ok(InterfacePattern::Regex(Regex::new(&pattern)?))
+        } else {
+            Ok(InterfacePattern::Literal{
+                pattern: if case_sensitive { pattern.to_string() } else { pattern.to_lowercase() },
+                whole_word,
+                case_sensitive,
+            })
+        }
+    }
+
+    fn matches(&self, name: &str) -> bool {
+        match self {
+            InterfacePattern::Regex(re) => re.is_match(name),
+            InterfacePattern::Literal{pattern, whole_word, case_sensitive} => {
+                let name = if *case_sensitive { name.to_string() } else { name.to_lowercase() };
+                if *whole_word {
+                    name.split(|c: char| !c.is_alphanumeric()).any(|word| word == pattern)
+                } else {
+                    name.contains(pattern.as_str())
+                }
+            },
+        }
+    }
 }
 
 
-#[derive(Subcommand)]
+#[derive(Subcommand, Clone)]
 enum Checks {
     /// checks CPU usage
     Cpu {
@@ -88,6 +237,14 @@ enum Checks {
         #[clap(flatten)]
         settings: Settings,
 
+        /// sensor name pattern to query, repeatable (default: "System Board 1 Inlet Temp")
+        #[clap(short, long)]
+        sensor: Vec<String>,
+
+        /// query every sensor instead of filtering by --sensor
+        #[clap(long)]
+        all_sensors: bool,
+
         /// warning threshold as integer (50°C)
         #[clap(short, long)]
         warning: Option<u32>,
@@ -102,6 +259,9 @@ enum Checks {
         #[clap(flatten)]
         settings: Settings,
 
+        #[clap(flatten)]
+        filter: InterfaceFilter,
+
         /// warning threshold as integer (1)
         #[clap(short, long)]
         warning: Option<u32>,
@@ -116,6 +276,9 @@ enum Checks {
         #[clap(flatten)]
         settings: Settings,
 
+        #[clap(flatten)]
+        filter: InterfaceFilter,
+
         /// warning threshold as integer (1)
         #[clap(short, long)]
         warning: Option<u32>,
@@ -144,6 +307,66 @@ enum Checks {
     },
 }
 
+/// A single raw, unit-free measurement collected while processing a check's rows.
+struct Measurement {
+    name: String,
+    value: f64,
+    labels: Vec<(String, String)>,
+}
+
+impl Measurement {
+    fn new(name: impl Into<String>, value: f64) -> Self {
+        Measurement{ name: name.into(), value, labels: Vec::new() }
+    }
+
+    fn with_label(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.labels.push((key.into(), value.into()));
+        self
+    }
+}
+
+/// The gathered result of processing a check's rows, not yet rendered into either
+/// Icinga's exit-code/perfdata format or a Prometheus exposition payload.
+struct CheckOutcome {
+    check_result: CheckResult,
+    info: Option<String>,
+    metrics: Vec<Metric>,
+    measurements: Vec<Measurement>,
+}
+
+/// Exits the process the way an Icinga plugin is expected to: exit code plus perfdata.
+fn render_icinga(outcome: CheckOutcome) -> ! {
+    let mut check_result = outcome.check_result;
+    if let Some(info) = outcome.info {
+        check_result = check_result.set_info(info);
+    }
+    exit(
+        check_result
+        .set_perf_data(PerfData::from_metrics(outcome.metrics))
+        .promote())
+}
+
+/// Escapes a label value per the Prometheus text exposition format: backslash and
+/// double-quote are backslash-escaped, newlines become a literal `\n`.
+fn escape_label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Prints the collected measurements in Prometheus text exposition format and exits 0.
+fn render_prometheus(check_name: &str, machine: &str, outcome: CheckOutcome) -> ! {
+    for measurement in &outcome.measurements {
+        let mut labels = vec![format!("host_name=\"{}\"", escape_label_value(machine))];
+        for (key, value) in &measurement.labels {
+            labels.push(format!("{}=\"{}\"", key, escape_label_value(value)));
+        }
+        println!("vspheredb_{}_{}{{{}}} {}", check_name, measurement.name, labels.join(","), measurement.value);
+    }
+    exit(0)
+}
+
 impl Deref for Checks {
     type Target = Settings;
     fn deref(&self) -> &Settings {
@@ -160,84 +383,136 @@ impl Deref for Checks {
 
 
 impl Checks {
-    /// Builds and returns a query for a given machine and a given check type
-    fn build_query(&self, machine: &String) -> String {
+    /// Short, stable name for this check type, used as the Prometheus metric name prefix.
+    fn name(&self) -> &'static str {
+        match self {
+            Checks::Cpu{..} => "cpu",
+            Checks::Memory{..} => "memory",
+            Checks::Temperature{..} => "temperature",
+            Checks::Nic{..} => "nic",
+            Checks::Hba{..} => "hba",
+            Checks::Datastore{..} => "datastore",
+        }
+    }
+
+    /// Returns the `[cpu]`/`[memory]`/... threshold table matching this check's type.
+    fn config_section<'a>(&self, config: &'a Option<ConfigFile>) -> Option<&'a ThresholdConfig> {
+        let cfg = config.as_ref()?;
+        match self {
+            Checks::Cpu{..} => cfg.cpu.as_ref(),
+            Checks::Memory{..} => cfg.memory.as_ref(),
+            Checks::Temperature{..} => cfg.temperature.as_ref(),
+            Checks::Nic{..} => cfg.nic.as_ref(),
+            Checks::Hba{..} => cfg.hba.as_ref(),
+            Checks::Datastore{..} => cfg.datastore.as_ref(),
+        }
+    }
+
+    /// Fills in any threshold left unset on the command line from the config file section.
+    fn merge_thresholds(self, section: Option<&ThresholdConfig>) -> Checks {
+        let (warn_cfg, crit_cfg) = section.map(|s| (s.warning, s.critical)).unwrap_or((None, None));
+        match self {
+            Checks::Cpu{settings, warning, critical} =>
+                Checks::Cpu{settings, warning: warning.or(warn_cfg), critical: critical.or(crit_cfg)},
+            Checks::Memory{settings, warning, critical} =>
+                Checks::Memory{settings, warning: warning.or(warn_cfg), critical: critical.or(crit_cfg)},
+            Checks::Temperature{settings, sensor, all_sensors, warning, critical} =>
+                Checks::Temperature{settings, sensor, all_sensors, warning: warning.or(warn_cfg), critical: critical.or(crit_cfg)},
+            Checks::Nic{settings, filter, warning, critical} =>
+                Checks::Nic{settings, filter, warning: warning.or(warn_cfg), critical: critical.or(crit_cfg)},
+            Checks::Hba{settings, filter, warning, critical} =>
+                Checks::Hba{settings, filter, warning: warning.or(warn_cfg), critical: critical.or(crit_cfg)},
+            Checks::Datastore{settings, store, warning, critical} =>
+                Checks::Datastore{settings, store, warning: warning.or(warn_cfg), critical: critical.or(crit_cfg)},
+        }
+    }
+
+    /// Builds a query template with `?` placeholders for a given machine and check type,
+    /// together with the bind parameters in the order they appear in the template.
+    fn build_query(&self, machine: &str) -> (String, Vec<String>) {
         let mut query = String::new();
+        let mut params: Vec<String> = Vec::new();
         match self {
             Checks::Cpu{..} => {
-                query.push_str("SELECT hqs.overall_cpu_usage, 
-                               hs.hardware_cpu_mhz, 
-                               hs.hardware_cpu_cores 
-                               FROM host_quick_stats hqs 
-                               INNER JOIN host_system hs 
-                               ON hqs.uuid = hs.uuid 
-                               WHERE hs.host_name LIKE \"");
-                query.push_str(machine);
-                query.push_str("\";");
-                return query;
+                query.push_str("SELECT hqs.overall_cpu_usage,
+                               hs.hardware_cpu_mhz,
+                               hs.hardware_cpu_cores
+                               FROM host_quick_stats hqs
+                               INNER JOIN host_system hs
+                               ON hqs.uuid = hs.uuid
+                               WHERE hs.host_name LIKE ?;");
+                params.push(machine.to_string());
             },
             Checks::Memory{..} => {
-                query.push_str("SELECT hqs.overall_memory_usage_mb, 
-                               hs.hardware_memory_size_mb 
-                               FROM host_quick_stats hqs 
-                               INNER JOIN host_system hs 
-                               ON hqs.uuid = hs.uuid 
-                               WHERE hs.host_name LIKE \"");
-                query.push_str(machine);
-                query.push_str("\";");
-                return query;
+                query.push_str("SELECT hqs.overall_memory_usage_mb,
+                               hs.hardware_memory_size_mb
+                               FROM host_quick_stats hqs
+                               INNER JOIN host_system hs
+                               ON hqs.uuid = hs.uuid
+                               WHERE hs.host_name LIKE ?;");
+                params.push(machine.to_string());
             },
-            Checks::Temperature{..} => {
-                query.push_str("SELECT se.current_reading 
-                               FROM host_sensor se 
-                               INNER JOIN host_system hs 
-                               ON se.host_uuid = hs.uuid 
-                               WHERE hs.host_name LIKE \"");
-                query.push_str(machine);
-                query.push_str("\" AND se.name LIKE \"System Board 1 Inlet Temp\"");
+            Checks::Temperature{sensor, all_sensors, ..} => {
+                query.push_str("SELECT se.name, se.current_reading
+                               FROM host_sensor se
+                               INNER JOIN host_system hs
+                               ON se.host_uuid = hs.uuid
+                               WHERE hs.host_name LIKE ?");
+                params.push(machine.to_string());
+                if !all_sensors {
+                    let default_sensor = vec![String::from("System Board 1 Inlet Temp")];
+                    let sensors = if sensor.is_empty() { &default_sensor } else { sensor };
+                    query.push_str(" AND (");
+                    for (i, s) in sensors.iter().enumerate() {
+                        if i > 0 {
+                            query.push_str(" OR ");
+                        }
+                        query.push_str("se.name LIKE ?");
+                        params.push(s.clone());
+                    }
+                    query.push_str(")");
+                }
                 query.push_str(";");
-                return query;
             },
             Checks::Nic{..} => {
-                query.push_str("SELECT hardware_num_nic 
-                               FROM host_system 
-                               WHERE host_system.host_name LIKE \"");
-                query.push_str(machine);
-                query.push_str("\";");
-                return query;
+                query.push_str("SELECT n.name, n.link_up, n.speed_mb
+                               FROM host_nic n
+                               INNER JOIN host_system hs
+                               ON n.host_uuid = hs.uuid
+                               WHERE hs.host_name LIKE ?;");
+                params.push(machine.to_string());
             },
             Checks::Hba{..} => {
-                query.push_str("SELECT hardware_num_hba 
-                               FROM host_system 
-                               WHERE host_system.host_name LIKE \"");
-                query.push_str(machine);
-                query.push_str("\";");
-                return query;
+                query.push_str("SELECT h.name
+                               FROM host_hba h
+                               INNER JOIN host_system hs
+                               ON h.host_uuid = hs.uuid
+                               WHERE hs.host_name LIKE ?;");
+                params.push(machine.to_string());
             },
             Checks::Datastore{store, ..} => {
-                query.push_str("SELECT o.object_name, ds.maintenance_mode, ds.capacity, ds.free_space 
-                               FROM datastore ds 
-                               INNER JOIN vcenter vc 
-                               ON ds.vcenter_uuid = vc.instance_uuid 
-                               INNER JOIN object o 
+                query.push_str("SELECT o.object_name, ds.maintenance_mode, ds.capacity, ds.free_space
+                               FROM datastore ds
+                               INNER JOIN vcenter vc
+                               ON ds.vcenter_uuid = vc.instance_uuid
+                               INNER JOIN object o
                                ON ds.uuid = o.uuid ");
                 if let Some(s) = store {
-                    query.push_str("WHERE o.object_name LIKE \"");
-                    query.push_str(s);
-                    query.push_str("\" AND ");
+                    query.push_str("WHERE o.object_name LIKE ? AND ");
+                    params.push(s.clone());
                 } else {
                     query.push_str("WHERE ")
                 }
-                query.push_str("vc.name LIKE \"");
-                query.push_str(machine);
-                query.push_str("\";");
-                return query;
+                query.push_str("vc.name LIKE ?;");
+                params.push(machine.to_string());
             },
         }
+        (query, params)
     }
 
-    fn process_results(self, rows: Vec<MySqlRow>) -> Result<(), sqlx::Error> {
+    fn process_results(self, rows: Vec<MySqlRow>) -> Result<CheckOutcome, sqlx::Error> {
         let mut metrics: Vec<Metric> = Vec::new();
+        let mut measurements: Vec<Measurement> = Vec::new();
         let status_msg: String;
         let warn: u32;
         let crit: u32;
@@ -258,22 +533,27 @@ impl Checks {
                         metrics.push(Metric::new(String::from("cores"), value2.to_string()));
 
                         status_msg = format!("Total CPU usage is {}GHz ({}%)", value0 / 1024, value);
+                        measurements.push(Measurement::new("usage", value0 as f64));
+                        measurements.push(Measurement::new("usage_percent", value as f64));
+                        measurements.push(Measurement::new("mhz", value1 as f64));
+                        measurements.push(Measurement::new("cores", value2 as f64));
                         let check_result = evaluate(value, warn, crit);
-                        exit(
-                            check_result.set_info(status_msg)
-                            .set_perf_data(PerfData::from_metrics(metrics))
-                            .promote())
+                        Ok(CheckOutcome{ check_result, info: Some(status_msg), metrics, measurements })
                     } else {
-                        exit(
-                            CheckResult::from(3)
-                            .set_info(String::from("No performance data found."))
-                            .promote())
+                        Ok(CheckOutcome{
+                            check_result: CheckResult::from(3),
+                            info: Some(String::from("No performance data found.")),
+                            metrics,
+                            measurements,
+                        })
                     }
                 } else {
-                    exit(
-                        CheckResult::from(3)
-                        .set_info(String::from("Query returned no results."))
-                        .promote())
+                    Ok(CheckOutcome{
+                        check_result: CheckResult::from(3),
+                        info: Some(String::from("Query returned no results.")),
+                        metrics,
+                        measurements,
+                    })
                 }
             },
             Checks::Memory{warning, critical, ..} => {
@@ -290,87 +570,156 @@ impl Checks {
                         metrics.push(Metric::new(String::from("capacity"), value1.to_string() + "MB"));
 
                         status_msg = format!("Total memory usage is {}GB ({}%)", value0 / 1024, value);
+                        measurements.push(Measurement::new("usage", value0 as f64));
+                        measurements.push(Measurement::new("usage_percent", value as f64));
+                        measurements.push(Measurement::new("capacity", value1 as f64));
                         let check_result = evaluate(value, warn, crit);
-                        exit(
-                            check_result.set_info(status_msg)
-                            .set_perf_data(PerfData::from_metrics(metrics))
-                            .promote())
+                        Ok(CheckOutcome{ check_result, info: Some(status_msg), metrics, measurements })
                     } else {
-                        exit(
-                            CheckResult::from(3)
-                            .set_info(String::from("No performance data found."))
-                            .promote())
+                        Ok(CheckOutcome{
+                            check_result: CheckResult::from(3),
+                            info: Some(String::from("No performance data found.")),
+                            metrics,
+                            measurements,
+                        })
                     }
                 } else {
-                    exit(
-                        CheckResult::from(3)
-                        .set_info(String::from("Query returned no results."))
-                        .promote())
+                    Ok(CheckOutcome{
+                        check_result: CheckResult::from(3),
+                        info: Some(String::from("Query returned no results.")),
+                        metrics,
+                        measurements,
+                    })
                 }
             },
             Checks::Temperature{warning, critical, ..} => {
-                if let Some(row) = rows.into_iter().nth(0) {
-                    warn = warning.unwrap_or(50);
-                    crit = critical.unwrap_or(60);
-                    let value: u32 = (row.get::<i32, usize>(0) / 100).try_into().unwrap();
-                    metrics.push(Metric::new(String::from("temp"), value.to_string() + "C")
+                if rows.is_empty() {
+                    return Ok(CheckOutcome{
+                        check_result: CheckResult::from(3),
+                        info: Some(String::from("Query returned no results.")),
+                        metrics,
+                        measurements,
+                    });
+                }
+                warn = warning.unwrap_or(50);
+                crit = critical.unwrap_or(60);
+
+                let mut breached: Vec<String> = Vec::new();
+                let mut check_result = CheckResult::from(0);
+                for row in rows {
+                    let name: &str = row.get(0);
+                    let value: u32 = (row.get::<i32, usize>(1) / 100).try_into().unwrap();
+                    metrics.push(Metric::new(name.to_string(), value.to_string() + "C")
                                  .warning(warn.to_string() + "C")
                                  .critical(crit.to_string() + "C"));
+                    measurements.push(Measurement::new("temp", value as f64).with_label("sensor", name.to_string()));
 
-                    status_msg = format!("Temperature is {}°C", value);
-                    let check_result = evaluate(value, warn, crit);
-                    exit(
-                        check_result.set_info(status_msg)
-                        .set_perf_data(PerfData::from_metrics(metrics))
-                        .promote())
-                } else {
-                    exit(
-                        CheckResult::from(3)
-                        .set_info(String::from("Query returned no results."))
-                        .promote())
+                    let sensor_result = evaluate(value, warn, crit);
+                    if !matches!(sensor_result.state(), State::OK) {
+                        breached.push(format!("{} is {}°C", name, value));
+                    }
+                    if <i32>::from(sensor_result.state()) > <i32>::from(check_result.state()) {
+                        check_result = sensor_result;
+                    }
                 }
-            },
-            Checks::Nic{warning, critical, ..} => {
-                if let Some(row) = rows.into_iter().nth(0) {
-                    warn = warning.unwrap_or(1);
-                    crit = critical.unwrap_or(0);
-                    let value: u8 = row.get(0);
-                    metrics.push(Metric::new(String::from("nics"), value.to_string())
-                                .warning(warn.to_string())
-                                .critical(crit.to_string()));
-
-                    let check_result = evaluate(value, warn, crit);
-                    exit(
-                        check_result.set_info(format!("Number of NICs: {}", value.to_string()))
-                        .set_perf_data(PerfData::from_metrics(metrics))
-                        .promote())
+
+                status_msg = if breached.is_empty() {
+                    format!("All {} temperature sensor(s) within thresholds", metrics.len())
                 } else {
-                    exit(
-                        CheckResult::from(3)
-                        .set_info(String::from("Query returned no results."))
-                        .promote())
-                }
+                    breached.join(", ")
+                };
+
+                Ok(CheckOutcome{ check_result, info: Some(status_msg), metrics, measurements })
             },
-            Checks::Hba{warning, critical, ..} => {
-                if let Some(row) = rows.into_iter().nth(0) {
-                    warn = warning.unwrap_or(1);
-                    crit = critical.unwrap_or(0);
-                    let value: u8 = row.get(0);
-                    metrics.push(Metric::new(String::from("hbas"), value.to_string())
-                                .warning(warn.to_string())
-                                .critical(crit.to_string()));
-
-                    let check_result = evaluate(value, warn, crit);
-                    exit(
-                        check_result.set_info(format!("Number of HBAs: {}", value.to_string()))
-                        .set_perf_data(PerfData::from_metrics(metrics))
-                        .promote())
+            Checks::Nic{filter, warning, critical, ..} => {
+                if rows.is_empty() {
+                    return Ok(CheckOutcome{
+                        check_result: CheckResult::from(3),
+                        info: Some(String::from("Query returned no results.")),
+                        metrics,
+                        measurements,
+                    });
+                }
+                warn = warning.unwrap_or(1);
+                crit = critical.unwrap_or(0);
+
+                let names: Vec<&str> = rows.iter().map(|row| row.get::<&str, usize>(0)).collect();
+                let surviving = match filter.apply(&names) {
+                    Ok(s) => s,
+                    Err(e) => return Ok(CheckOutcome{
+                        check_result: CheckResult::from(3),
+                        info: Some(format!("Invalid interface filter pattern: {}", e)),
+                        metrics,
+                        measurements,
+                    }),
+                };
+
+                let mut up_count: u32 = 0;
+                let mut down_interfaces: Vec<String> = Vec::new();
+                for row in rows.iter() {
+                    let name: &str = row.get(0);
+                    if !surviving.contains(&name) {
+                        continue;
+                    }
+                    let link_up: bool = row.get(1);
+                    let speed_mb: u32 = row.get(2);
+                    metrics.push(Metric::new(name.to_string(), if link_up { String::from("1") } else { String::from("0") }));
+                    measurements.push(Measurement::new("nic_up", if link_up { 1.0 } else { 0.0 }).with_label("interface", name.to_string()));
+                    if link_up {
+                        up_count += 1;
+                    } else {
+                        down_interfaces.push(format!("{} ({}Mb/s)", name, speed_mb));
+                    }
+                }
+                metrics.push(Metric::new(String::from("up_nics"), up_count.to_string())
+                            .warning(warn.to_string())
+                            .critical(crit.to_string()));
+                measurements.push(Measurement::new("up_nics", up_count as f64));
+
+                status_msg = if down_interfaces.is_empty() {
+                    format!("{} of {} NICs up", up_count, surviving.len())
                 } else {
-                    exit(
-                        CheckResult::from(3)
-                        .set_info(String::from("Query returned no results."))
-                        .promote())
+                    format!("{} of {} NICs up, down: {}", up_count, surviving.len(), down_interfaces.join(", "))
+                };
+
+                let check_result = evaluate(up_count, warn, crit);
+                Ok(CheckOutcome{ check_result, info: Some(status_msg), metrics, measurements })
+            },
+            Checks::Hba{filter, warning, critical, ..} => {
+                if rows.is_empty() {
+                    return Ok(CheckOutcome{
+                        check_result: CheckResult::from(3),
+                        info: Some(String::from("Query returned no results.")),
+                        metrics,
+                        measurements,
+                    });
+                }
+                warn = warning.unwrap_or(1);
+                crit = critical.unwrap_or(0);
+
+                let names: Vec<&str> = rows.iter().map(|row| row.get::<&str, usize>(0)).collect();
+                let surviving = match filter.apply(&names) {
+                    Ok(s) => s,
+                    Err(e) => return Ok(CheckOutcome{
+                        check_result: CheckResult::from(3),
+                        info: Some(format!("Invalid interface filter pattern: {}", e)),
+                        metrics,
+                        measurements,
+                    }),
+                };
+
+                let value: u32 = surviving.len() as u32;
+                for name in &surviving {
+                    metrics.push(Metric::new(name.to_string(), String::from("1")));
+                    measurements.push(Measurement::new("hba_present", 1.0).with_label("hba", name.to_string()));
                 }
+                metrics.push(Metric::new(String::from("hbas"), value.to_string())
+                            .warning(warn.to_string())
+                            .critical(crit.to_string()));
+                measurements.push(Measurement::new("hbas", value as f64));
+
+                let check_result = evaluate(value, warn, crit);
+                Ok(CheckOutcome{ check_result, info: Some(format!("Number of HBAs: {}", value)), metrics, measurements })
             },
             Checks::Datastore{store, warning, critical, ..} => {
                 warn = warning.unwrap_or(80);
@@ -388,17 +737,22 @@ impl Checks {
                                      .warning(warn.to_string())
                                      .critical(crit.to_string()));
                         metrics.push(Metric::new(String::from("maintenance_mode"), maintenance_mode.to_string()));
+                        measurements.push(Measurement::new("used_percent", used_percent as f64).with_label("datastore", s.clone()));
 
                         let check_result = evaluate(used_percent, warn, crit);
-                        exit(
-                            check_result.set_info(format!("Used storage space for datastore {} (mode: {}): {}%", s, maintenance_mode, used_percent.to_string()))
-                            .set_perf_data(PerfData::from_metrics(metrics))
-                            .promote())
+                        Ok(CheckOutcome{
+                            check_result,
+                            info: Some(format!("Used storage space for datastore {} (mode: {}): {}%", s, maintenance_mode, used_percent.to_string())),
+                            metrics,
+                            measurements,
+                        })
                     } else {
-                        exit(
-                            CheckResult::from(3)
-                            .set_info(String::from("Query returned no results."))
-                            .promote())
+                        Ok(CheckOutcome{
+                            check_result: CheckResult::from(3),
+                            info: Some(String::from("Query returned no results.")),
+                            metrics,
+                            measurements,
+                        })
                     }
                 } else {
                     let mut output_string = String::new();
@@ -413,7 +767,8 @@ impl Checks {
                             .warning(warn.to_string())
                             .critical(crit.to_string()));
                         metrics.push(Metric::new(format!("{}_maintenance_mode", name), maintenance_mode.to_string()));
-                        
+                        measurements.push(Measurement::new("used_percent", used_percent as f64).with_label("datastore", name.to_string()));
+
                         let new_check_result = evaluate(used_percent, warn, crit);
                         match new_check_result.state() {
                                 State::OK => (),
@@ -426,52 +781,173 @@ impl Checks {
                         }
                     }
 
-                    if output_string != String::from("") {
-                        check_result = check_result.set_info(output_string);
-                    }
-                    if metrics != Vec::new() {
-                        check_result = check_result.set_perf_data(PerfData::from_metrics(metrics));
-                    }
-
-                    exit(check_result.promote())
+                    let info = if output_string.is_empty() { None } else { Some(output_string) };
+                    Ok(CheckOutcome{ check_result, info, metrics, measurements })
                 }
             },
         }
     }
 }
 
+/// Splits `--machine` into the list of hosts to check: a comma-separated list is used
+/// verbatim, a single `%` SQL wildcard pattern is expanded against the column the check
+/// type actually queries by, and anything else is a single literal host.
+async fn resolve_hosts(pool: &MySqlPool, check: &Checks, machine: &str) -> Result<Vec<String>, sqlx::Error> {
+    if machine.contains(',') {
+        return Ok(machine.split(',').map(|m| m.trim().to_string()).filter(|m| !m.is_empty()).collect());
+    }
+    if machine.contains('%') {
+        let query = match check {
+            Checks::Datastore{..} => "SELECT DISTINCT name FROM vcenter WHERE name LIKE ?;",
+            _ => "SELECT DISTINCT host_name FROM host_system WHERE host_name LIKE ?;",
+        };
+        let rows = sqlx::query(query)
+            .bind(machine)
+            .fetch_all(pool)
+            .await?;
+        return Ok(rows.iter().map(|row| row.get::<String, usize>(0)).collect());
+    }
+    Ok(vec![machine.to_string()])
+}
+
+/// Runs a single host's query against the shared pool and processes its rows.
+async fn run_check(pool: &MySqlPool, check: &Checks, host: &str) -> Result<(String, CheckOutcome), sqlx::Error> {
+    let (query, params) = check.build_query(host);
+    let mut bound_query = sqlx::query(&query);
+    for param in &params {
+        bound_query = bound_query.bind(param);
+    }
+    let rows = bound_query.fetch_all(pool).await?;
+    let outcome = check.clone().process_results(rows)?;
+    Ok((host.to_string(), outcome))
+}
+
+/// Renders the per-host outcomes of a batch run as one multiline Icinga result: each
+/// host's status line is prefixed with its name, and the overall exit code is the worst
+/// individual host's state.
+fn render_icinga_batch(outcomes: Vec<(String, CheckOutcome)>) -> ! {
+    let mut worst = CheckResult::from(0);
+    let mut lines: Vec<String> = Vec::new();
+    let mut metrics: Vec<Metric> = Vec::new();
+    for (host, outcome) in outcomes {
+        let line = outcome.info.unwrap_or_else(|| String::from("OK"));
+        lines.push(format!("{}: {}", host, line));
+        metrics.extend(outcome.metrics);
+        if <i32>::from(outcome.check_result.state()) > <i32>::from(worst.state()) {
+            worst = outcome.check_result;
+        }
+    }
+    exit(
+        worst.set_info(lines.join("\n"))
+        .set_perf_data(PerfData::from_metrics(metrics))
+        .promote())
+}
+
+/// Renders the per-host outcomes of a batch run in Prometheus text exposition format,
+/// one `host_name` label per host, then exits 0.
+fn render_prometheus_batch(check_name: &str, outcomes: Vec<(String, CheckOutcome)>) -> ! {
+    for (host, outcome) in outcomes {
+        for measurement in &outcome.measurements {
+            let mut labels = vec![format!("host_name=\"{}\"", escape_label_value(&host))];
+            for (key, value) in &measurement.labels {
+                labels.push(format!("{}=\"{}\"", key, escape_label_value(value)));
+            }
+            println!("vspheredb_{}_{}{{{}}} {}", check_name, measurement.name, labels.join(","), measurement.value);
+        }
+    }
+    exit(0)
+}
+
 #[async_std::main]
 async fn main() -> Result<(), sqlx::Error> {
     let args = App::parse();
-    let query = args.check.build_query(&args.machine);
-  
-    let mut conn: MySqlConnection; 
-    let mut address = String::from("mysql://");
-    address += &args.check.user;
-    address.push_str(":");
-    address += &args.check.password;
-    address.push_str("@");
-    address += &args.check.host;
-    address.push_str(":");
-    address += &args.check.port.to_string();
-    address.push_str("/");
-    address += &args.check.database;
-    match MySqlConnection::connect(&address).await {
-        Ok(c) => {
-            conn = c;
-            let mut query_result = sqlx::query(&query).fetch(&mut conn);
-            let mut result_collection = Vec::new();  
-            while let Some(row) = query_result.try_next().await? {
-                result_collection.push(row);
-            }
 
-            args.check.process_results(result_collection)?;
+    let config: Option<ConfigFile> = match &args.config {
+        Some(path) => match load_config(path) {
+            Ok(c) => Some(c),
+            Err(e) => exit(CheckResult::from(3).set_info(e).promote()),
         },
-        Err(e) => 
-            exit(
-                CheckResult::from(2)
-                .set_info(format!("Could not connect to database: {}", e))
-                .promote())
+        None => None,
     };
-    Ok(())
+    let db_config = config.as_ref().and_then(|c| c.database.as_ref());
+
+    let host = args.check.host.clone()
+        .or_else(|| db_config.and_then(|d| d.host.clone()))
+        .unwrap_or_else(|| "localhost".to_string());
+    let port = args.check.port
+        .or_else(|| db_config.and_then(|d| d.port))
+        .unwrap_or(3306);
+    let database = args.check.database.clone()
+        .or_else(|| db_config.and_then(|d| d.database.clone()))
+        .unwrap_or_else(|| "vspheredb".to_string());
+    let user = args.check.user.clone()
+        .or_else(|| db_config.and_then(|d| d.user.clone()))
+        .unwrap_or_else(|| "vspheredb".to_string());
+    let password = args.check.password.clone()
+        .or_else(|| db_config.and_then(|d| d.password.clone()))
+        .unwrap_or_else(|| "vspheredb".to_string());
+
+    let section = args.check.config_section(&config);
+    let check = args.check.merge_thresholds(section);
+    let check_name = check.name();
+
+    let statement_cache_capacity = match args.statement_cache.as_str() {
+        "unbounded" => usize::MAX,
+        "disabled" => 0,
+        other => exit(
+            CheckResult::from(3)
+            .set_info(format!("Invalid --statement-cache value: {} (expected \"unbounded\" or \"disabled\")", other))
+            .promote()),
+    };
+    let connect_options = MySqlConnectOptions::new()
+        .host(&host)
+        .port(port)
+        .username(&user)
+        .password(&password)
+        .database(&database)
+        .statement_cache_capacity(statement_cache_capacity);
+
+    let pool = match MySqlPoolOptions::new()
+        .max_connections(args.pool_size)
+        .connect_with(connect_options)
+        .await
+    {
+        Ok(p) => p,
+        Err(e) => exit(
+            CheckResult::from(2)
+            .set_info(format!("Could not connect to database: {}", e))
+            .promote()),
+    };
+
+    let hosts = resolve_hosts(&pool, &check, &args.machine).await?;
+    if hosts.is_empty() {
+        exit(
+            CheckResult::from(3)
+            .set_info(String::from("No hosts matched --machine."))
+            .promote())
+    }
+    if hosts.len() == 1 {
+        let (_, outcome) = run_check(&pool, &check, &hosts[0]).await?;
+        match args.export.as_str() {
+            "prometheus" => render_prometheus(check_name, &hosts[0], outcome),
+            _ => render_icinga(outcome),
+        }
+    }
+
+    let max_concurrency = args.max_concurrency.max(1);
+    let outcomes: Vec<(String, CheckOutcome)> = stream::iter(hosts.iter().map(|host| {
+        let pool = pool.clone();
+        let check = check.clone();
+        async move { run_check(&pool, &check, host).await }
+    }))
+    .buffer_unordered(max_concurrency)
+    .collect::<Vec<Result<(String, CheckOutcome), sqlx::Error>>>()
+    .await
+    .into_iter()
+    .collect::<Result<Vec<(String, CheckOutcome)>, sqlx::Error>>()?;
+
+    match args.export.as_str() {
+        "prometheus" => render_prometheus_batch(check_name, outcomes),
+        _ => render_icinga_batch(outcomes),
+    }
 }